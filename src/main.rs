@@ -1,24 +1,53 @@
 use std::{
     collections::HashMap,
     fmt::format,
-    io::{Read, Write},
+    io::{self, Read, Write},
     net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread,
     time::{Duration, Instant},
 };
 
-use base64::prelude::*;
 use clap::Parser;
 use rand::{distributions::Alphanumeric, Rng};
 
+mod rdb;
+
 type Store = Arc<Mutex<HashMap<String, (String, Option<Instant>)>>>;
 
+// A replica that has completed the PSYNC handshake: `stream` is used to
+// propagate writes to it, `acked_offset` is updated as `REPLCONF ACK`
+// replies come back on the same connection.
+struct Replica {
+    stream: TcpStream,
+    acked_offset: Arc<Mutex<usize>>,
+}
+type Replicas = Arc<Mutex<Vec<Replica>>>;
+
 const REPL_ID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
-const RDB_64: &str = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
 
-fn init_store() -> Store {
-    Arc::new(Mutex::new(HashMap::new()))
+// Loads the keyspace from the RDB file at `dir/dbfilename`, if one exists.
+// A missing file just means an empty keyspace, same as a fresh `dump.rdb`.
+fn init_store(config: &Config) -> Store {
+    let mut keyspace = HashMap::new();
+
+    let path = rdb_path(config);
+    match std::fs::read(&path) {
+        Ok(bytes) => {
+            for entry in rdb::parse(&bytes) {
+                keyspace.insert(entry.key, (entry.value, entry.expiry));
+            }
+            println!("loaded {} keys from {}", keyspace.len(), path.display());
+        }
+        Err(e) => println!("no RDB file loaded from {} ({e})", path.display()),
+    }
+
+    Arc::new(Mutex::new(keyspace))
+}
+
+fn rdb_path(config: &Config) -> PathBuf {
+    Path::new(&config.dir).join(&config.dbfilename)
 }
 
 #[derive(Parser, Debug)]
@@ -29,11 +58,15 @@ struct Config {
     port: u16,
     #[arg(long, num_args = 2, value_name = "REPLICA_HOST REPLICA_PORT")]
     replicaof: Option<Vec<String>>,
+    #[arg(long, default_value = ".")]
+    dir: String,
+    #[arg(long, default_value = "dump.rdb")]
+    dbfilename: String,
 }
 
 fn main() {
     let config = dbg!(Config::parse());
-    let store = init_store();
+    let store = init_store(&config);
     let server = Server::init(config, store);
 
     server.start();
@@ -43,15 +76,25 @@ fn main() {
 enum Role {
     Master {
         master_replid: String,
-        master_repl_offset: usize,
+        // Shared across every connection thread so propagated writes
+        // advance one consistent counter.
+        master_repl_offset: Arc<Mutex<usize>>,
+    },
+    Slave {
+        master_host: String,
+        master_port: u16,
+        // Bytes of the replication stream applied so far; reported back to
+        // the master on `REPLCONF GETACK`.
+        replica_offset: Arc<Mutex<usize>>,
     },
-    Slave((String, u16)),
 }
 
 struct Server {
     role: Role,
     listener: TcpListener,
     store: Store,
+    replicas: Replicas,
+    rdb_path: PathBuf,
 }
 
 impl Server {
@@ -62,7 +105,11 @@ impl Server {
                 let host = replica.first().expect("shoud contain host");
                 let port = replica.last().expect("should contain port");
                 let port: u16 = port.parse().expect("port should be valid");
-                Role::Slave((host.to_owned(), port))
+                Role::Slave {
+                    master_host: host.to_owned(),
+                    master_port: port,
+                    replica_offset: Arc::new(Mutex::new(0)),
+                }
             }
             None => {
                 let random_string: String = rand::thread_rng()
@@ -73,98 +120,39 @@ impl Server {
 
                 Role::Master {
                     master_replid: random_string,
-                    master_repl_offset: 0,
+                    master_repl_offset: Arc::new(Mutex::new(0)),
                 }
             }
         };
 
-        Server::init_handshake(&config, &role);
-        println!("handshake success");
+        // A replica's link to the master is supervised in the background:
+        // the handshake and the live command stream both run inside
+        // `run_replica`'s reconnect loop so a dropped connection or a
+        // master that isn't up yet doesn't block the server from starting.
+        if let Role::Slave {
+            master_host,
+            master_port,
+            replica_offset,
+        } = &role
+        {
+            let master_host = master_host.to_owned();
+            let master_port = *master_port;
+            let self_port = config.port;
+            let store = store.clone();
+            let offset = replica_offset.clone();
+            thread::spawn(move || {
+                run_replica(&master_host, master_port, self_port, store, offset);
+            });
+        }
+
+        let rdb_path = rdb_path(&config);
 
         Server {
             role,
             listener,
             store,
-        }
-    }
-
-    fn init_handshake(config: &Config, role: &Role) {
-        match role {
-            Role::Master {
-                master_replid,
-                master_repl_offset,
-            } => {
-                return;
-            }
-            Role::Slave((master_host, master_port)) => {
-                let self_port = config.port;
-                let mut read_buf = vec![0; 1024];
-                let mut stream =
-                    TcpStream::connect((master_host.to_owned(), master_port.to_owned()))
-                        .expect("failed to connect to master");
-
-                // Do PING
-                println!("init ping");
-                let op = format!("*1\r\n$4\r\nping\r\n");
-                stream
-                    .write_all(op.as_bytes())
-                    .expect("should be able to write to master");
-                let _ = stream.read(&mut read_buf).expect("should get some message");
-                println!("read_buf: {read_buf:?}");
-                let resp = String::from_utf8(read_buf.to_owned()).unwrap();
-                println!("resp: {resp:?}");
-                if !resp.to_lowercase().contains("pong") {
-                    panic!("did not receive pong");
-                }
-                // read_buf.iter_mut().for_each(|x| *x = 0);
-
-                // Do REPLCONF
-                println!("init first replconf");
-                let op = format!(
-                    "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n{}\r\n",
-                    self_port
-                );
-                stream
-                    .write_all(op.as_bytes())
-                    .expect("should be able to write to master");
-                let _ = stream.read(&mut read_buf).expect("should get some message");
-                println!("read_buf: {read_buf:?}");
-                let resp = String::from_utf8(read_buf.to_owned()).unwrap();
-                println!("resp: {resp:?}");
-                if !resp.to_lowercase().contains("ok") {
-                    panic!("did not receive ok");
-                }
-                // read_buf.iter_mut().for_each(|x| *x = 0);
-
-                // Do REPLCONF
-                println!("init second replconf");
-                let op = format!("*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n");
-                stream
-                    .write_all(op.as_bytes())
-                    .expect("should be able to write to master");
-                let _ = stream.read(&mut read_buf).expect("should get some message");
-                println!("read_buf: {read_buf:?}");
-                let resp = String::from_utf8(read_buf.to_owned()).unwrap();
-                println!("resp: {resp:?}");
-                if !resp.to_lowercase().contains("ok") {
-                    panic!("did not receive ok");
-                }
-                // read_buf.iter_mut().for_each(|x| *x = 0);
-
-                // Do PSYNC
-                println!("init psync");
-                let op = format!("*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n");
-                stream
-                    .write_all(op.as_bytes())
-                    .expect("should be able to write to master");
-                let _ = stream.read(&mut read_buf).expect("should get some message");
-                println!("read_buf: {read_buf:?}");
-                // let resp = String::from_utf8(read_buf.to_owned()).unwrap();
-                // println!("resp: {resp:?}");
-                // if !resp.to_lowercase().contains("fullresync") {
-                //     panic!("did not receive fullresync");
-                // }
-            }
+            replicas: Arc::new(Mutex::new(Vec::new())),
+            rdb_path,
         }
     }
 
@@ -175,8 +163,10 @@ impl Server {
                     println!("new connection: {:?}", stream.peer_addr());
                     let store = self.store.clone();
                     let role = self.role.clone();
+                    let replicas = self.replicas.clone();
+                    let rdb_path = self.rdb_path.clone();
                     thread::spawn(move || {
-                        handle_connection(stream, store, role);
+                        handle_connection(stream, store, role, replicas, rdb_path);
                     });
                 }
                 Err(e) => {
@@ -190,12 +180,21 @@ impl Server {
 
 // Note: `fn fn_name(var: mut Type)` is invalid. mut is used to denote mutability
 // of variables and references, not types.
-fn handle_connection(mut stream: TcpStream, store: Store, role: Role) {
+fn handle_connection(
+    mut stream: TcpStream,
+    store: Store,
+    role: Role,
+    replicas: Replicas,
+    rdb_path: PathBuf,
+) {
     // Note: The buffer onto which TcpStream::read function is called must have
     // non-zero len! Initializing buffer like `let mut buffer =
     // Vec::with_capacity(1024)` will not work since the lenght of buffer will
     // still be zero.
-    let mut buf = vec![0; 1024];
+    let mut read_buf = vec![0; 1024];
+    // Bytes carried over from previous reads that don't yet form a complete
+    // frame, plus any bytes left over after a pipelined read.
+    let mut pending = Vec::new();
     // // Sets TCP_NODELAY at kernel level. TCP_NODELAY basically disables Nagle's
     // // algorithm.
     // //
@@ -207,45 +206,82 @@ fn handle_connection(mut stream: TcpStream, store: Store, role: Role) {
     // // low latency and send small packets frequently.
     stream.set_nodelay(true).unwrap();
 
+    // Once a connection completes PSYNC it stops being a normal client and
+    // becomes a replica link: every further frame on it is a `REPLCONF ACK`
+    // reply rather than a command, so we only track its acked offset.
+    let mut replica_acked_offset: Option<Arc<Mutex<usize>>> = None;
+
     loop {
         // Rust differentiates between Vec<T> and &mut Vec<T>. Implicit coercion
         // from Vec<T> to &mut Vec<T> doesn't occur, but &mut Vec<T> can be coerced
         // to &mut U if Vec<T> implements DerefMut<Target=U>.
-        match stream.read(&mut buf) {
+        match stream.read(&mut read_buf) {
+            Ok(0) => {
+                println!("connection closed: {:?}", stream.peer_addr());
+                return;
+            }
             Ok(n) => {
-                if n == 0 {
-                    println!("waiting for more data...");
-                    thread::sleep(Duration::from_secs_f64(0.5));
-                    continue;
+                pending.extend_from_slice(&read_buf[..n]);
+
+                // Drain as many complete frames as the buffer holds so that
+                // pipelined commands in a single read are all handled before
+                // we block on the socket again.
+                loop {
+                    match Resp::decode(&pending) {
+                        DecodeResult::Incomplete => break,
+                        DecodeResult::Frame { value, consumed } => {
+                            if let Some(acked_offset) = &replica_acked_offset {
+                                apply_replica_ack(&value, acked_offset);
+                            } else {
+                                replica_acked_offset = handle_data(
+                                    &mut stream,
+                                    value,
+                                    &store,
+                                    &role,
+                                    &replicas,
+                                    &rdb_path,
+                                );
+                            }
+                            pending.drain(..consumed);
+                        }
+                    }
                 }
-                handle_data(&mut stream, &buf, &store, &role);
-                buf.iter_mut().for_each(|x| *x = 0);
             }
-            Err(_) => todo!(),
+            Err(e) => {
+                println!("read error on {:?}: {e}", stream.peer_addr());
+                return;
+            }
         }
     }
 
     // TODO:
     // 1. handle all unwarps
-    // 2. support creating of `Resp` message from &str
-    // 3. clean up get and set operations
-    fn handle_data(stream: &mut TcpStream, buf: &[u8], store: &Store, role: &Role) {
-        let incoming_message =
-            String::from_utf8(buf.to_owned()).expect("Failed to construct message");
-        let incoming_message = incoming_message.trim_end().trim_end_matches('\0');
-        println!("incoming message: {incoming_message:?}");
-
-        let (resp, _residual) = Resp::new(incoming_message);
-        match resp {
+    // 2. clean up get and set operations
+    // Returns `Some(acked_offset)` once the connection has completed PSYNC
+    // and been registered as a replica, signalling the caller to stop
+    // treating it as a normal client and instead track its ACKs.
+    fn handle_data(
+        stream: &mut TcpStream,
+        resp: Resp,
+        store: &Store,
+        role: &Role,
+        replicas: &Replicas,
+        rdb_path: &Path,
+    ) -> Option<Arc<Mutex<usize>>> {
+        println!("incoming frame: {resp:?}");
+
+        match &resp {
             Resp::SimpleString(s) => {
                 if s.to_lowercase().contains("ping") {
                     handle_ping(stream)
                 }
+                None
             }
             Resp::BulkString(s) => {
                 if s.to_lowercase().contains("ping") {
                     handle_ping(stream)
                 }
+                None
             }
             Resp::Array(arr) => {
                 let mut arr_iter = arr.iter();
@@ -263,22 +299,80 @@ fn handle_connection(mut stream: TcpStream, store: Store, role: Role) {
                     handle_echo(stream, arr_iter);
                 } else if message.contains("info") {
                     handle_info(stream, arr_iter, role)
+                } else if message.contains("wait") {
+                    handle_wait(stream, arr_iter, role, replicas)
                 } else if message.contains("set") {
-                    handle_set(stream, arr_iter, store)
+                    handle_set(stream, arr_iter, store);
+                    propagate_write(role, replicas, arr);
                 } else if message.contains("get") {
                     handle_get(stream, arr_iter, store)
+                } else if message.contains("save") {
+                    handle_save(stream, store, rdb_path)
                 } else if message.contains("replconf") {
-                    // TODO: do this only for Master
-                    handle_replconf(stream, arr_iter)
+                    handle_replconf(stream, arr_iter, role)
                 } else if message.contains("psync") {
-                    handle_psync(stream, arr_iter)
+                    return Some(handle_psync(stream, arr_iter, store, replicas));
                 }
+
+                None
             }
+            Resp::Error(_) | Resp::Integer(_) | Resp::Null => None,
+        }
+    }
+
+    // Applies a `REPLCONF ACK <offset>` frame received from a replica on its
+    // registered connection; anything else on this connection is ignored.
+    fn apply_replica_ack(resp: &Resp, acked_offset: &Arc<Mutex<usize>>) {
+        let Resp::Array(arr) = resp else {
+            return;
+        };
+        let mut it = arr.iter();
+
+        let Some(command) = it.next().and_then(Resp::get_string) else {
+            return;
+        };
+        if !command.to_lowercase().contains("replconf") {
+            return;
+        }
+
+        let Some(sub) = it.next().and_then(Resp::get_string) else {
+            return;
+        };
+        if !sub.to_lowercase().contains("ack") {
+            return;
+        }
+
+        if let Some(offset) = it.next().and_then(Resp::get_string).and_then(|s| s.parse().ok()) {
+            *acked_offset.lock().expect("offset is poisoned!") = offset;
         }
     }
 
+    // Forwards a write command received on a `Role::Master` to every
+    // connected replica, dropping any replica whose socket has gone bad, and
+    // advances `master_repl_offset` by the bytes just sent.
+    fn propagate_write(role: &Role, replicas: &Replicas, arr: &[Resp]) {
+        let Role::Master {
+            master_repl_offset, ..
+        } = role
+        else {
+            return;
+        };
+
+        let command: Vec<Resp> = arr
+            .iter()
+            .map(|item| Resp::BulkString(item.get_string().unwrap_or_default()))
+            .collect();
+        let payload = Resp::Array(command).encode();
+
+        let mut reps = replicas.lock().expect("Replicas is poisoned!");
+        reps.retain_mut(|replica| replica.stream.write_all(&payload).is_ok());
+        drop(reps);
+
+        *master_repl_offset.lock().expect("offset is poisoned!") += payload.len();
+    }
+
     fn handle_ping(stream: &mut TcpStream) {
-        let _ = stream.write_all("+PONG\r\n".as_bytes());
+        let _ = stream.write_all(&Resp::SimpleString("PONG".to_owned()).encode());
     }
 
     fn handle_echo<'a, T>(stream: &mut TcpStream, mut it: T)
@@ -286,9 +380,7 @@ fn handle_connection(mut stream: TcpStream, store: Store, role: Role) {
         T: Iterator<Item = &'a Resp>,
     {
         let message = it.next().unwrap().get_string().unwrap();
-        let len = message.len();
-        let op = format!("${len}\r\n{message}\r\n");
-        let _ = stream.write_all(op.as_bytes());
+        let _ = stream.write_all(&Resp::BulkString(message).encode());
     }
 
     fn handle_info<'a, T>(stream: &mut TcpStream, mut it: T, role: &Role)
@@ -297,18 +389,18 @@ fn handle_connection(mut stream: TcpStream, store: Store, role: Role) {
     {
         let info_type = it.next().unwrap().get_string().unwrap();
         if info_type == "replication" {
-            let op = match role {
+            let body = match role {
                 Role::Master {
                     master_replid,
                     master_repl_offset,
                 } => {
-                    let count = 11 + 1 + 54 + 1 + 20;
-                    format!("${count}\r\nrole:master\nmaster_replid:{master_replid}\nmaster_repl_offset:{master_repl_offset}\r\n")
+                    let offset = *master_repl_offset.lock().expect("offset is poisoned!");
+                    format!("role:master\nmaster_replid:{master_replid}\nmaster_repl_offset:{offset}")
                 }
-                Role::Slave(_) => format!("$10\r\nrole:slave\r\n"),
+                Role::Slave { .. } => "role:slave".to_owned(),
             };
 
-            let _ = stream.write_all(op.as_bytes());
+            let _ = stream.write_all(&Resp::BulkString(body).encode());
         }
     }
 
@@ -334,7 +426,7 @@ fn handle_connection(mut stream: TcpStream, store: Store, role: Role) {
         let expiry_time = expiry.map(|delta| Instant::now() + Duration::from_millis(delta as u64));
 
         s.insert(key, (val, expiry_time));
-        let _ = stream.write_all("+OK\r\n".as_bytes());
+        let _ = stream.write_all(&Resp::SimpleString("OK".to_owned()).encode());
     }
 
     fn handle_get<'a, T>(stream: &mut TcpStream, mut it: T, store: &Store)
@@ -343,67 +435,479 @@ fn handle_connection(mut stream: TcpStream, store: Store, role: Role) {
     {
         let key = it.next().unwrap().get_string().unwrap();
         let s = store.lock().expect("Store is poisoned!");
-        if let Some((val, expiry)) = s.get(&key) {
-            if expiry.is_some() && Instant::now() > expiry.unwrap() {
-                let _ = stream.write_all("$-1\r\n".as_bytes());
-            } else {
-                let len = val.len();
-                let op = format!("${len}\r\n{val}\r\n");
-                let _ = stream.write_all(op.as_bytes());
+        let resp = match s.get(&key) {
+            Some((val, expiry)) if !expiry.is_some_and(|at| Instant::now() > at) => {
+                Resp::BulkString(val.to_owned())
+            }
+            _ => Resp::Null,
+        };
+        let _ = stream.write_all(&resp.encode());
+    }
+
+    // `SAVE`: writes the current keyspace to `rdb_path` as an RDB dump.
+    fn handle_save(stream: &mut TcpStream, store: &Store, rdb_path: &Path) {
+        let bytes = {
+            let keyspace = store.lock().expect("Store is poisoned!");
+            rdb::build(keyspace.iter())
+        };
+
+        match std::fs::write(rdb_path, &bytes) {
+            Ok(()) => {
+                let _ = stream.write_all(&Resp::SimpleString("OK".to_owned()).encode());
+            }
+            Err(e) => {
+                let _ = stream.write_all(&Resp::Error(format!("ERR {e}")).encode());
             }
-        } else {
-            let _ = stream.write_all("$-1\r\n".as_bytes());
         }
     }
 
-    fn handle_replconf<'a, T>(stream: &mut TcpStream, mut it: T)
+    fn handle_replconf<'a, T>(stream: &mut TcpStream, mut it: T, role: &Role)
     where
         T: Iterator<Item = &'a Resp>,
     {
-        let _ = stream.write_all("+OK\r\n".as_bytes());
+        // Only a master fields handshake REPLCONF from a connecting
+        // replica; a replica's own listener has nothing to say here.
+        if matches!(role, Role::Master { .. }) {
+            let _ = stream.write_all(&Resp::SimpleString("OK".to_owned()).encode());
+        }
     }
 
-    fn handle_psync<'a, T>(stream: &mut TcpStream, mut it: T)
+    fn handle_psync<'a, T>(
+        stream: &mut TcpStream,
+        mut it: T,
+        store: &Store,
+        replicas: &Replicas,
+    ) -> Arc<Mutex<usize>>
     where
         T: Iterator<Item = &'a Resp>,
     {
-        let rdb = BASE64_STANDARD.decode(RDB_64).unwrap();
-        // let rdb_str: String = rdb.iter().map(|n| format!("{n:08b}")).collect();
+        let rdb = {
+            let keyspace = store.lock().expect("Store is poisoned!");
+            rdb::build(keyspace.iter())
+        };
         let op = format!("+FULLRESYNC {REPL_ID} 0\r\n${}\r\n", rdb.len());
         let _ = stream.write(op.as_bytes());
         let _ = stream.write(&rdb);
         println!("writing: {op}");
-        let _ = stream.write_all(op.as_bytes());
+
+        // From here on this socket carries propagated writes (and GETACK
+        // pokes) rather than normal request/response traffic, so register
+        // it for write propagation and start tracking its acked offset.
+        let acked_offset = Arc::new(Mutex::new(0));
+        match stream.try_clone() {
+            Ok(replica_stream) => {
+                replicas.lock().expect("Replicas is poisoned!").push(Replica {
+                    stream: replica_stream,
+                    acked_offset: acked_offset.clone(),
+                });
+            }
+            Err(e) => println!("failed to register replica: {e}"),
+        }
+
+        acked_offset
+    }
+
+    // `WAIT numreplicas timeout_ms`: pokes every replica for its current
+    // offset and reports how many have caught up to the offset the master
+    // was at when WAIT was called, waiting at most `timeout_ms`.
+    fn handle_wait<'a, T>(stream: &mut TcpStream, mut it: T, role: &Role, replicas: &Replicas)
+    where
+        T: Iterator<Item = &'a Resp>,
+    {
+        let numreplicas: usize = it
+            .next()
+            .and_then(Resp::get_string)
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0);
+        let timeout_ms: u64 = it
+            .next()
+            .and_then(Resp::get_string)
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0);
+
+        let Role::Master {
+            master_repl_offset, ..
+        } = role
+        else {
+            let _ = stream.write_all(&Resp::Integer(0).encode());
+            return;
+        };
+
+        let target_offset = *master_repl_offset.lock().expect("offset is poisoned!");
+
+        // The GETACK poke itself is part of the replication stream, so it
+        // advances the master offset like any other propagated command.
+        let getack = Resp::Array(vec![
+            Resp::BulkString("REPLCONF".to_owned()),
+            Resp::BulkString("GETACK".to_owned()),
+            Resp::BulkString("*".to_owned()),
+        ])
+        .encode();
+        {
+            let mut reps = replicas.lock().expect("Replicas is poisoned!");
+            reps.retain_mut(|replica| replica.stream.write_all(&getack).is_ok());
+        }
+        *master_repl_offset.lock().expect("offset is poisoned!") += getack.len();
+
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let acked = loop {
+            let acked = {
+                let reps = replicas.lock().expect("Replicas is poisoned!");
+                reps.iter()
+                    .filter(|r| *r.acked_offset.lock().expect("offset is poisoned!") >= target_offset)
+                    .count()
+            };
+
+            if acked >= numreplicas || Instant::now() >= deadline {
+                break acked;
+            }
+
+            thread::sleep(Duration::from_millis(20));
+        };
+
+        let _ = stream.write_all(&Resp::Integer(acked as i64).encode());
+    }
+}
+
+// Supervises the replica side of the master link for as long as the
+// process lives: connects, completes the PSYNC handshake, loads the
+// snapshot it returns, then streams propagated commands until the
+// connection drops, at which point it reconnects with exponential backoff
+// and resyncs from scratch. This is what keeps a replica alive across
+// master restarts and flaky links.
+fn run_replica(
+    master_host: &str,
+    master_port: u16,
+    self_port: u16,
+    store: Store,
+    offset: Arc<Mutex<usize>>,
+) {
+    let mut backoff = Duration::from_millis(200);
+    const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+    loop {
+        match handshake(master_host, master_port, self_port) {
+            Ok((stream, snapshot, pending)) => {
+                println!("handshake with master succeeded");
+                backoff = Duration::from_millis(200);
+
+                {
+                    let mut keyspace = store.lock().expect("Store is poisoned!");
+                    keyspace.clear();
+                    for entry in rdb::parse(&snapshot) {
+                        keyspace.insert(entry.key, (entry.value, entry.expiry));
+                    }
+                }
+                *offset.lock().expect("offset is poisoned!") = 0;
+
+                // Blocks until the stream errors or the master closes it,
+                // which is exactly the signal to reconnect and resync.
+                run_replica_stream(stream, store.clone(), offset.clone(), pending);
+            }
+            Err(e) => println!("replica handshake failed: {e}"),
+        }
+
+        println!("reconnecting to master in {backoff:?}");
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Connects to the master and runs the PING -> REPLCONF -> REPLCONF ->
+// PSYNC handshake, reading each reply frame-by-frame via `Resp::decode`
+// rather than assuming a single `read` contains the whole response.
+// Returns the live connection, the raw RDB snapshot bytes that followed
+// the FULLRESYNC reply, and any bytes read past the end of the snapshot
+// (the master may coalesce the first propagated writes into the same
+// TCP segment as the RDB payload) so the caller can load the snapshot
+// before replaying those leftover bytes as part of the replication
+// stream.
+fn handshake(
+    master_host: &str,
+    master_port: u16,
+    self_port: u16,
+) -> io::Result<(TcpStream, Vec<u8>, Vec<u8>)> {
+    let mut stream = TcpStream::connect((master_host, master_port))?;
+    let mut pending = Vec::new();
+
+    let ping = Resp::Array(vec![Resp::BulkString("ping".to_owned())]).encode();
+    stream.write_all(&ping)?;
+    read_frame(&mut stream, &mut pending)?;
+
+    let listening_port = Resp::Array(vec![
+        Resp::BulkString("REPLCONF".to_owned()),
+        Resp::BulkString("listening-port".to_owned()),
+        Resp::BulkString(self_port.to_string()),
+    ])
+    .encode();
+    stream.write_all(&listening_port)?;
+    read_frame(&mut stream, &mut pending)?;
+
+    let capa = Resp::Array(vec![
+        Resp::BulkString("REPLCONF".to_owned()),
+        Resp::BulkString("capa".to_owned()),
+        Resp::BulkString("psync2".to_owned()),
+    ])
+    .encode();
+    stream.write_all(&capa)?;
+    read_frame(&mut stream, &mut pending)?;
+
+    let psync = Resp::Array(vec![
+        Resp::BulkString("PSYNC".to_owned()),
+        Resp::BulkString("?".to_owned()),
+        Resp::BulkString("-1".to_owned()),
+    ])
+    .encode();
+    stream.write_all(&psync)?;
+    // The FULLRESYNC reply is a simple string; the RDB snapshot that
+    // immediately follows isn't itself a RESP frame (its payload has no
+    // trailing CRLF), so it's read separately below.
+    read_frame(&mut stream, &mut pending)?;
+    let snapshot = read_rdb_snapshot(&mut stream, &mut pending)?;
+
+    Ok((stream, snapshot, pending))
+}
+
+// Reads bytes off `stream` into `pending` until a complete frame is
+// available at the front of it, then returns that frame and drains its
+// bytes. Used during the handshake, where replies arrive one at a time
+// rather than through the connection's own decode loop.
+fn read_frame(stream: &mut TcpStream, pending: &mut Vec<u8>) -> io::Result<Resp> {
+    let mut read_buf = vec![0; 1024];
+    loop {
+        match Resp::decode(pending) {
+            DecodeResult::Frame { value, consumed } => {
+                pending.drain(..consumed);
+                return Ok(value);
+            }
+            DecodeResult::Incomplete => {
+                let n = stream.read(&mut read_buf)?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "master closed connection during handshake",
+                    ));
+                }
+                pending.extend_from_slice(&read_buf[..n]);
+            }
+        }
     }
 }
 
+// Reads the `$<len>\r\n<bytes>` snapshot that follows a FULLRESYNC reply.
+// It looks like a bulk string but isn't one (no trailing CRLF after the
+// payload), so it needs its own reader rather than going through
+// `Resp::decode`.
+fn read_rdb_snapshot(stream: &mut TcpStream, pending: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut read_buf = vec![0; 1024];
+
+    let header_end = loop {
+        if let Some(end) = find_crlf(pending) {
+            break end;
+        }
+        let n = stream.read(&mut read_buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "master closed connection during handshake",
+            ));
+        }
+        pending.extend_from_slice(&read_buf[..n]);
+    };
+
+    let len: usize = std::str::from_utf8(&pending[1..header_end])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid RDB snapshot length"))?;
+    pending.drain(..header_end + 2);
+
+    while pending.len() < len {
+        let n = stream.read(&mut read_buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "master closed connection during handshake",
+            ));
+        }
+        pending.extend_from_slice(&read_buf[..n]);
+    }
+
+    Ok(pending.drain(..len).collect())
+}
+
+// Drives the replica side of an established PSYNC connection: applies every
+// propagated command to `store` and tracks how many bytes of the stream
+// have been consumed so `REPLCONF GETACK` can be answered accurately.
+fn run_replica_stream(
+    mut stream: TcpStream,
+    store: Store,
+    offset: Arc<Mutex<usize>>,
+    mut pending: Vec<u8>,
+) {
+    let mut read_buf = vec![0; 1024];
+
+    loop {
+        // Drain whatever `pending` already holds before blocking on the
+        // socket again. This also covers the bytes `handshake` seeded us
+        // with: the master may have coalesced the first propagated
+        // writes into the same segment as the RDB snapshot.
+        loop {
+            match Resp::decode(&pending) {
+                DecodeResult::Incomplete => break,
+                DecodeResult::Frame { value, consumed } => {
+                    // The command's own bytes count toward the offset
+                    // before it's applied, so a GETACK that arrives as
+                    // part of this frame reports itself.
+                    *offset.lock().expect("offset is poisoned!") += consumed;
+                    apply_replicated_command(&mut stream, &value, &store, &offset);
+                    pending.drain(..consumed);
+                }
+            }
+        }
+
+        match stream.read(&mut read_buf) {
+            Ok(0) => {
+                println!("replication stream closed by master");
+                return;
+            }
+            Ok(n) => {
+                pending.extend_from_slice(&read_buf[..n]);
+            }
+            Err(e) => {
+                println!("replication stream read error: {e}");
+                return;
+            }
+        }
+    }
+}
+
+fn apply_replicated_command(stream: &mut TcpStream, resp: &Resp, store: &Store, offset: &Arc<Mutex<usize>>) {
+    let Resp::Array(arr) = resp else {
+        return;
+    };
+    let mut it = arr.iter();
+
+    let Some(message) = it.next().and_then(Resp::get_string) else {
+        return;
+    };
+    let message = message.to_lowercase();
+
+    if message.contains("set") {
+        replica_apply_set(it, store);
+    } else if message.contains("replconf") {
+        let Some(sub) = it.next().and_then(Resp::get_string) else {
+            return;
+        };
+        if sub.to_lowercase().contains("getack") {
+            let current_offset = *offset.lock().expect("offset is poisoned!");
+            let op = Resp::Array(vec![
+                Resp::BulkString("REPLCONF".to_owned()),
+                Resp::BulkString("ACK".to_owned()),
+                Resp::BulkString(current_offset.to_string()),
+            ])
+            .encode();
+            let _ = stream.write_all(&op);
+        }
+    }
+}
+
+fn replica_apply_set<'a, T>(mut it: T, store: &Store)
+where
+    T: Iterator<Item = &'a Resp>,
+{
+    let Some(key) = it.next().and_then(Resp::get_string) else {
+        return;
+    };
+    let Some(val) = it.next().and_then(Resp::get_string) else {
+        return;
+    };
+
+    let expiry: Option<usize> = match it.next() {
+        Some(exp) => {
+            if exp
+                .get_string()
+                .unwrap_or_default()
+                .to_lowercase()
+                .contains("px")
+            {
+                it.next().and_then(Resp::get_string).and_then(|s| s.parse().ok())
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+
+    let expiry_time = expiry.map(|delta| Instant::now() + Duration::from_millis(delta as u64));
+    store
+        .lock()
+        .expect("Store is poisoned!")
+        .insert(key, (val, expiry_time));
+}
+
 /// Implementation of the REDIS protocol
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Resp {
     SimpleString(String),
     BulkString(String),
+    Error(String),
+    Integer(i64),
+    // Covers both the null bulk string (`$-1\r\n`) and the null array
+    // (`*-1\r\n`) — this server only ever needs to emit the former, but
+    // decodes either into the same value.
+    Null,
     Array(Vec<Resp>),
 }
 
+/// Result of feeding the decoder another chunk of bytes: either the buffer
+/// doesn't hold a full frame yet, or it does and we know exactly how many
+/// bytes of the buffer that frame consumed.
+enum DecodeResult {
+    Incomplete,
+    Frame { value: Resp, consumed: usize },
+}
+
 impl Resp {
-    // Returns data type and residual data if any
-    pub fn new(input: &str) -> (Self, String) {
-        let (message_type, data) = input.split_at(1);
+    // Incrementally decodes a single frame from the front of `buf`. Returns
+    // `Incomplete` rather than panicking when `buf` doesn't yet contain a
+    // full frame; callers should hold on to the buffer, read more bytes, and
+    // try again.
+    fn decode(buf: &[u8]) -> DecodeResult {
+        let Some(&message_type) = buf.first() else {
+            return DecodeResult::Incomplete;
+        };
 
         match message_type {
-            "+" => {
-                let (d, res) = Self::parse_simple_string(data);
-                (Resp::SimpleString(d), res)
-            }
-            "$" => {
-                let (d, res) = Self::parse_bulk_string(data);
-                (Resp::BulkString(d), res)
-            }
-            "*" => {
-                let (d, res) = Self::parse_array(data);
-                (Resp::Array(d), res)
+            b'+' => Self::decode_simple_string(buf),
+            b'-' => Self::decode_error(buf),
+            b':' => Self::decode_integer(buf),
+            b'$' => Self::decode_bulk_string(buf),
+            b'*' => Self::decode_array(buf),
+            _ => DecodeResult::Frame {
+                value: Resp::Error(format!(
+                    "ERR Protocol error: unknown type byte '{}'",
+                    message_type as char
+                )),
+                consumed: 1,
+            },
+        }
+    }
+
+    // Serializes this value back to wire bytes.
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Resp::SimpleString(s) => format!("+{s}\r\n").into_bytes(),
+            Resp::Error(s) => format!("-{s}\r\n").into_bytes(),
+            Resp::Integer(n) => format!(":{n}\r\n").into_bytes(),
+            Resp::Null => b"$-1\r\n".to_vec(),
+            Resp::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
+            Resp::Array(items) => {
+                let mut out = format!("*{}\r\n", items.len()).into_bytes();
+                for item in items {
+                    out.extend(item.encode());
+                }
+                out
             }
-            _ => todo!(),
         }
     }
 
@@ -415,52 +919,156 @@ impl Resp {
         }
     }
 
-    fn parse_simple_string(input: &str) -> (String, String) {
-        let (data, residual) = input.split_once("\r\n").unwrap();
-        (data.to_owned(), residual.to_owned())
+    fn decode_simple_string(buf: &[u8]) -> DecodeResult {
+        let Some(end) = find_crlf(&buf[1..]) else {
+            return DecodeResult::Incomplete;
+        };
+
+        let data = String::from_utf8_lossy(&buf[1..1 + end]).into_owned();
+        DecodeResult::Frame {
+            value: Resp::SimpleString(data),
+            consumed: 1 + end + 2,
+        }
     }
 
-    fn parse_bulk_string(input: &str) -> (String, String) {
-        let (data, residual) = input.split_once("\r\n").unwrap();
-        let size: usize = data.parse().unwrap();
+    fn decode_error(buf: &[u8]) -> DecodeResult {
+        let Some(end) = find_crlf(&buf[1..]) else {
+            return DecodeResult::Incomplete;
+        };
 
-        let (data, residual) = residual.split_once("\r\n").unwrap();
-        assert_eq!(size, data.len());
+        let data = String::from_utf8_lossy(&buf[1..1 + end]).into_owned();
+        DecodeResult::Frame {
+            value: Resp::Error(data),
+            consumed: 1 + end + 2,
+        }
+    }
+
+    fn decode_integer(buf: &[u8]) -> DecodeResult {
+        let Some(end) = find_crlf(&buf[1..]) else {
+            return DecodeResult::Incomplete;
+        };
+        let consumed = 1 + end + 2;
+        let Some(n) = std::str::from_utf8(&buf[1..1 + end])
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+        else {
+            return DecodeResult::Frame {
+                value: Resp::Error("ERR Protocol error: invalid integer".to_owned()),
+                consumed,
+            };
+        };
+
+        DecodeResult::Frame {
+            value: Resp::Integer(n),
+            consumed,
+        }
+    }
+
+    fn decode_bulk_string(buf: &[u8]) -> DecodeResult {
+        let Some(len_end) = find_crlf(&buf[1..]) else {
+            return DecodeResult::Incomplete;
+        };
+        let Some(size) = std::str::from_utf8(&buf[1..1 + len_end])
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+        else {
+            return DecodeResult::Frame {
+                value: Resp::Error("ERR Protocol error: invalid bulk length".to_owned()),
+                consumed: 1 + len_end + 2,
+            };
+        };
+
+        if size == -1 {
+            return DecodeResult::Frame {
+                value: Resp::Null,
+                consumed: 1 + len_end + 2,
+            };
+        }
+        let size = size as usize;
+
+        // The declared length is the number of payload bytes, which may
+        // include embedded `\r\n` sequences, so we must not split on the
+        // first CRLF we see.
+        let data_start = 1 + len_end + 2;
+        let data_end = data_start + size;
+        if buf.len() < data_end + 2 {
+            return DecodeResult::Incomplete;
+        }
 
-        (data.to_owned(), residual.to_owned())
+        let data = String::from_utf8_lossy(&buf[data_start..data_end]).into_owned();
+        DecodeResult::Frame {
+            value: Resp::BulkString(data),
+            consumed: data_end + 2,
+        }
     }
 
-    fn parse_array(input: &str) -> (Vec<Resp>, String) {
-        let (data, residual) = input.split_once("\r\n").unwrap();
-        let size: usize = data.parse().unwrap();
+    fn decode_array(buf: &[u8]) -> DecodeResult {
+        let Some(len_end) = find_crlf(&buf[1..]) else {
+            return DecodeResult::Incomplete;
+        };
+        let Some(size) = std::str::from_utf8(&buf[1..1 + len_end])
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+        else {
+            return DecodeResult::Frame {
+                value: Resp::Error("ERR Protocol error: invalid multibulk length".to_owned()),
+                consumed: 1 + len_end + 2,
+            };
+        };
+
+        if size == -1 {
+            return DecodeResult::Frame {
+                value: Resp::Null,
+                consumed: 1 + len_end + 2,
+            };
+        }
+        let size = size as usize;
 
-        let mut elements = Vec::new();
-        let mut residual = residual.to_owned();
+        let mut consumed = 1 + len_end + 2;
+        let mut elements = Vec::with_capacity(size);
 
         for _ in 0..size {
-            let (item, res) = Resp::new(&residual);
-            elements.push(item);
-            residual = res;
+            match Resp::decode(&buf[consumed..]) {
+                DecodeResult::Incomplete => return DecodeResult::Incomplete,
+                DecodeResult::Frame { value, consumed: n } => {
+                    elements.push(value);
+                    consumed += n;
+                }
+            }
         }
 
-        (elements, residual.to_owned())
+        DecodeResult::Frame {
+            value: Resp::Array(elements),
+            consumed,
+        }
     }
 }
 
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
 #[test]
 fn resp_test() {
-    let simple = "+PONG\r\n";
-    let (result, _) = Resp::new(simple);
-    assert_eq!(result, Resp::SimpleString("PONG".to_owned()));
+    let simple = b"+PONG\r\n";
+    let DecodeResult::Frame { value, consumed } = Resp::decode(simple) else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::SimpleString("PONG".to_owned()));
+    assert_eq!(consumed, simple.len());
 
-    let bulk = "$5\r\nhello\r\n";
-    let (result, _) = Resp::new(bulk);
-    assert_eq!(result, Resp::BulkString("hello".to_owned()));
+    let bulk = b"$5\r\nhello\r\n";
+    let DecodeResult::Frame { value, .. } = Resp::decode(bulk) else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::BulkString("hello".to_owned()));
 
-    let array = "*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n";
-    let (result, _) = Resp::new(array);
+    let array = b"*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n";
+    let DecodeResult::Frame { value, .. } = Resp::decode(array) else {
+        panic!("expected a complete frame");
+    };
     assert_eq!(
-        result,
+        value,
         Resp::Array(vec![
             Resp::BulkString("hello".to_owned()),
             Resp::BulkString("world".to_owned()),
@@ -469,18 +1077,81 @@ fn resp_test() {
 }
 
 #[test]
-fn decode() {
-    let buf = [
-        43, 70, 85, 76, 76, 82, 69, 83, 89, 78, 67, 32, 120, 117, 116, 100, 100, 48, 106, 117, 48,
-        52, 48, 102, 118, 99, 122, 115, 98, 108, 114, 98, 49, 106, 113, 106, 101, 54, 108, 98, 106,
-        107, 115, 116, 55, 119, 120, 122, 99, 56, 113, 113, 32, 48, 13, 10, 36, 56, 56, 13, 10, 82,
-        69, 68, 73, 83, 48, 48, 49, 49, 250, 9, 114, 101, 100, 105, 115, 45, 118, 101, 114, 5, 55,
-        46, 50, 46, 48, 250, 10, 114, 101, 100, 105, 115, 45, 98, 105, 116, 115, 192, 64, 250, 5,
-        99, 116, 105, 109, 101, 194, 109, 8, 188, 101, 250, 8, 117, 115, 101, 100, 45, 109, 101,
-        109, 194, 176, 196, 16, 0, 250, 8, 97, 111, 102, 45, 98, 97, 115, 101, 192, 0, 255, 240,
-        110, 59, 254, 192, 255, 90, 162, 0,
-    ];
-
-    let string = String::from_utf16(&buf.to_vec()).unwrap();
-    println!("{string:?}");
+fn resp_full_type_set_test() {
+    let DecodeResult::Frame { value, .. } = Resp::decode(b"-ERR oops\r\n") else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::Error("ERR oops".to_owned()));
+
+    let DecodeResult::Frame { value, .. } = Resp::decode(b":1000\r\n") else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::Integer(1000));
+
+    let DecodeResult::Frame { value, .. } = Resp::decode(b"$-1\r\n") else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::Null);
+
+    let DecodeResult::Frame { value, .. } = Resp::decode(b"*-1\r\n") else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::Null);
+}
+
+#[test]
+fn resp_encode_test() {
+    assert_eq!(
+        Resp::SimpleString("OK".to_owned()).encode(),
+        b"+OK\r\n".to_vec()
+    );
+    assert_eq!(
+        Resp::Error("ERR oops".to_owned()).encode(),
+        b"-ERR oops\r\n".to_vec()
+    );
+    assert_eq!(Resp::Integer(42).encode(), b":42\r\n".to_vec());
+    assert_eq!(Resp::Null.encode(), b"$-1\r\n".to_vec());
+    assert_eq!(
+        Resp::BulkString("hello".to_owned()).encode(),
+        b"$5\r\nhello\r\n".to_vec()
+    );
+    assert_eq!(
+        Resp::Array(vec![
+            Resp::BulkString("hello".to_owned()),
+            Resp::BulkString("world".to_owned()),
+        ])
+        .encode(),
+        b"*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n".to_vec()
+    );
+}
+
+#[test]
+fn resp_partial_frame_test() {
+    // A bulk string whose payload hasn't fully arrived yet must not panic.
+    let partial = b"$5\r\nhel";
+    assert!(matches!(Resp::decode(partial), DecodeResult::Incomplete));
+
+    // A bulk string payload containing an embedded CRLF must be read by
+    // length, not split on the first CRLF found.
+    let embedded = b"$6\r\nhe\r\nlo\r\n";
+    let DecodeResult::Frame { value, consumed } = Resp::decode(embedded) else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::BulkString("he\r\nlo".to_owned()));
+    assert_eq!(consumed, embedded.len());
+}
+
+#[test]
+fn resp_pipelined_frames_test() {
+    let buf = b"+PONG\r\n+PONG\r\n";
+    let DecodeResult::Frame { value, consumed } = Resp::decode(buf) else {
+        panic!("expected a complete frame");
+    };
+    assert_eq!(value, Resp::SimpleString("PONG".to_owned()));
+    assert!(consumed < buf.len());
+
+    let DecodeResult::Frame { value, .. } = Resp::decode(&buf[consumed..]) else {
+        panic!("expected a second complete frame");
+    };
+    assert_eq!(value, Resp::SimpleString("PONG".to_owned()));
 }
@@ -0,0 +1,279 @@
+//! Minimal reader/writer for the subset of the RDB file format this server
+//! needs: the magic header, aux/select-db/resizedb opcodes (read and
+//! discarded), string keys/values with optional expiry, and the EOF +
+//! checksum trailer.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_EXPIRE_SECONDS: u8 = 0xFD;
+const OP_SELECT_DB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+const VALUE_TYPE_STRING: u8 = 0;
+
+/// A single key loaded from an RDB file, with its expiry already converted
+/// to this process's monotonic clock.
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub expiry: Option<Instant>,
+}
+
+/// Parses `bytes` as an RDB dump, returning every key that hasn't already
+/// expired. Malformed or truncated input just yields whatever entries were
+/// read before the parser gave up, rather than panicking.
+pub fn parse(bytes: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut cursor = Cursor::new(bytes);
+
+    // 9-byte magic ("REDIS") + 4 ASCII version digits.
+    if cursor.take(9).is_none() {
+        return entries;
+    }
+
+    let mut pending_expire_ms: Option<u64> = None;
+
+    while let Some(opcode) = cursor.read_u8() {
+        match opcode {
+            OP_EOF => break,
+            OP_SELECT_DB => {
+                cursor.read_length();
+            }
+            OP_RESIZEDB => {
+                cursor.read_length();
+                cursor.read_length();
+            }
+            OP_AUX => {
+                if cursor.read_string().is_none() || cursor.read_string().is_none() {
+                    break;
+                }
+            }
+            OP_EXPIRE_SECONDS => {
+                let Some(bytes) = cursor.take(4) else {
+                    break;
+                };
+                let seconds = u32::from_le_bytes(bytes.try_into().unwrap());
+                pending_expire_ms = Some(seconds as u64 * 1000);
+            }
+            OP_EXPIRE_MS => {
+                let Some(bytes) = cursor.take(8) else {
+                    break;
+                };
+                pending_expire_ms = Some(u64::from_le_bytes(bytes.try_into().unwrap()));
+            }
+            VALUE_TYPE_STRING => {
+                let Some(key) = cursor.read_string() else {
+                    break;
+                };
+                let Some(value) = cursor.read_string() else {
+                    break;
+                };
+
+                match pending_expire_ms.take() {
+                    Some(epoch_ms) => {
+                        if let Some(expiry) = epoch_ms_to_instant(epoch_ms) {
+                            entries.push(Entry { key, value, expiry: Some(expiry) });
+                        }
+                        // Already expired: drop it, same as a real server
+                        // would on load.
+                    }
+                    None => entries.push(Entry { key, value, expiry: None }),
+                }
+            }
+            // Value types other than string aren't supported by this
+            // server, so there's nothing sound to skip to.
+            _ => break,
+        }
+    }
+
+    entries
+}
+
+/// Serializes `entries` as a complete RDB dump (magic, one `SELECTDB 0`,
+/// every key/value, EOF marker and an unchecked zero checksum).
+pub fn build<'a>(entries: impl Iterator<Item = (&'a String, &'a (String, Option<Instant>))>) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(b"REDIS0011");
+
+    out.push(OP_SELECT_DB);
+    write_length(&mut out, 0);
+
+    for (key, (value, expiry)) in entries {
+        if let Some(expiry) = expiry {
+            out.push(OP_EXPIRE_MS);
+            out.extend_from_slice(&instant_to_epoch_ms(*expiry).to_le_bytes());
+        }
+        out.push(VALUE_TYPE_STRING);
+        write_string(&mut out, key);
+        write_string(&mut out, value);
+    }
+
+    out.push(OP_EOF);
+    out.extend_from_slice(&[0u8; 8]); // CRC64 checksum; readers here don't validate it.
+    out
+}
+
+fn epoch_ms_to_instant(epoch_ms: u64) -> Option<Instant> {
+    let now_epoch_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock should be after the unix epoch")
+        .as_millis() as u64;
+
+    if epoch_ms <= now_epoch_ms {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(epoch_ms - now_epoch_ms))
+    }
+}
+
+fn instant_to_epoch_ms(expiry: Instant) -> u64 {
+    let remaining = expiry.saturating_duration_since(Instant::now());
+    let now_epoch_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock should be after the unix epoch");
+    (now_epoch_ms + remaining).as_millis() as u64
+}
+
+enum Length {
+    Len(u64),
+    SpecialEncoding(u8),
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Cursor { buf, pos: 0 }
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        let byte = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let slice = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(slice)
+    }
+
+    // Length encoding: the top two bits of the first byte select 6-bit
+    // inline (`00`), 14-bit big-endian (`01`), a following 32-bit or 64-bit
+    // length (`10`), or a special integer encoding of the string that
+    // follows (`11`).
+    fn read_length(&mut self) -> Option<Length> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0b00 => Some(Length::Len((first & 0x3F) as u64)),
+            0b01 => {
+                let second = self.read_u8()?;
+                Some(Length::Len((((first & 0x3F) as u64) << 8) | second as u64))
+            }
+            0b10 if first == 0x80 => {
+                let bytes = self.take(4)?;
+                Some(Length::Len(u32::from_be_bytes(bytes.try_into().ok()?) as u64))
+            }
+            0b10 => {
+                let bytes = self.take(8)?;
+                Some(Length::Len(u64::from_be_bytes(bytes.try_into().ok()?)))
+            }
+            _ => Some(Length::SpecialEncoding(first & 0x3F)),
+        }
+    }
+
+    // Reads a length-encoded string, honoring the special integer
+    // encodings (8/16/32-bit little-endian integers stored in place of a
+    // length-prefixed string). LZF-compressed strings aren't supported.
+    fn read_string(&mut self) -> Option<String> {
+        match self.read_length()? {
+            Length::Len(len) => {
+                let bytes = self.take(len as usize)?;
+                Some(String::from_utf8_lossy(bytes).into_owned())
+            }
+            Length::SpecialEncoding(0) => Some((self.read_u8()? as i8).to_string()),
+            Length::SpecialEncoding(1) => {
+                let bytes = self.take(2)?;
+                Some(i16::from_le_bytes(bytes.try_into().ok()?).to_string())
+            }
+            Length::SpecialEncoding(2) => {
+                let bytes = self.take(4)?;
+                Some(i32::from_le_bytes(bytes.try_into().ok()?).to_string())
+            }
+            Length::SpecialEncoding(_) => None,
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 64 {
+        out.push(len as u8);
+    } else if len < 16384 {
+        out.push(0b0100_0000 | ((len >> 8) as u8));
+        out.push((len & 0xFF) as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_length(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[test]
+fn round_trips_strings_without_expiry() {
+    let store: Vec<(String, (String, Option<Instant>))> = vec![
+        ("foo".to_owned(), ("bar".to_owned(), None)),
+        ("baz".to_owned(), ("qux".to_owned(), None)),
+    ];
+
+    let bytes = build(store.iter().map(|(k, v)| (k, v)));
+    let mut entries = parse(&bytes);
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].key, "baz");
+    assert_eq!(entries[0].value, "qux");
+    assert_eq!(entries[1].key, "foo");
+    assert_eq!(entries[1].value, "bar");
+}
+
+#[test]
+fn keeps_an_unexpired_key_and_drops_an_expired_one() {
+    let far_future = Instant::now() + Duration::from_secs(3600);
+    let store: Vec<(String, (String, Option<Instant>))> = vec![
+        ("fresh".to_owned(), ("value".to_owned(), Some(far_future))),
+        ("stale".to_owned(), ("value".to_owned(), Some(Instant::now()))),
+    ];
+
+    let bytes = build(store.iter().map(|(k, v)| (k, v)));
+    let entries = parse(&bytes);
+
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].key, "fresh");
+    assert!(entries[0].expiry.is_some());
+}
+
+#[test]
+fn length_encoding_round_trips_across_all_size_classes() {
+    for len in [0u64, 63, 64, 16383, 16384, u32::MAX as u64, u32::MAX as u64 + 1] {
+        let mut out = Vec::new();
+        write_length(&mut out, len);
+        let mut cursor = Cursor::new(&out);
+        let Length::Len(decoded) = cursor.read_length().unwrap() else {
+            panic!("expected a plain length for {len}");
+        };
+        assert_eq!(decoded, len);
+    }
+}